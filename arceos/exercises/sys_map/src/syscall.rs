@@ -1,15 +1,20 @@
 #![allow(dead_code)]
 
+extern crate alloc;
+
 use core::ffi::{c_void, c_char, c_int};
 use axhal::arch::TrapFrame;
-use axhal::trap::{register_trap_handler, SYSCALL};
+use axhal::trap::{register_trap_handler, PAGE_FAULT, SYSCALL};
 use axerrno::LinuxError;
 use axtask::current;
 use axtask::TaskExtRef;
 use axhal::paging::MappingFlags;
 use arceos_posix_api::{self as api, get_file_like};
-use memory_addr::{MemoryAddr, VirtAddr, VirtAddrRange};
+use memory_addr::{MemoryAddr, PhysAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
+use kspin::SpinNoIrq;
 use axstd::vec;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
 
 const SYS_IOCTL: usize = 29;
 const SYS_OPENAT: usize = 56;
@@ -21,6 +26,8 @@ const SYS_EXIT: usize = 93;
 const SYS_EXIT_GROUP: usize = 94;
 const SYS_SET_TID_ADDRESS: usize = 96;
 const SYS_MMAP: usize = 222;
+const SYS_MUNMAP: usize = 215;
+const SYS_MPROTECT: usize = 226;
 
 const AT_FDCWD: i32 = -100;
 
@@ -98,6 +105,63 @@ bitflags::bitflags! {
     }
 }
 
+/// A virtual memory area recorded by `sys_mmap`.
+///
+/// The mapping is populated lazily: `sys_mmap` only stores the descriptor and
+/// the page-fault handler allocates and fills physical frames on demand.
+struct Vma {
+    /// The virtual address range covered by this mapping.
+    range: VirtAddrRange,
+    /// Backing file descriptor, or `-1` for an anonymous mapping.
+    fd: i32,
+    /// Offset into the backing file for the first byte of `range`.
+    offset: isize,
+    /// Requested protection bits.
+    prot: MmapProt,
+    /// Requested mapping flags.
+    flags: MmapFlags,
+}
+
+/// All virtual memory areas registered by `sys_mmap`.
+///
+/// These exercises run a single user address space, so a global list is enough
+/// to stand in for the per-aspace VMA list a full kernel would keep.
+static VMAS: SpinNoIrq<Vec<Vma>> = SpinNoIrq::new(Vec::new());
+
+/// Reference count of physical frames shared copy-on-write between mappings,
+/// keyed by physical address. A frame with a count above one is read-only and
+/// must be duplicated before the first write.
+static FRAME_REFS: SpinNoIrq<BTreeMap<usize, usize>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Allocate one physical frame from the global allocator for COW use.
+///
+/// The frame is mapped into user space with [`axmm::AddrSpace::map_linear`] so
+/// that `unmap` never returns it to the allocator; its lifetime is governed
+/// solely by the [`FRAME_REFS`] count and [`free_frame`].
+fn alloc_frame() -> Option<PhysAddr> {
+    let vaddr = axalloc::global_allocator().alloc_pages(1, PAGE_SIZE_4K).ok()?;
+    Some(axhal::mem::virt_to_phys(vaddr.into()))
+}
+
+/// Return a COW frame to the global allocator once its last mapping is gone.
+fn free_frame(paddr: PhysAddr) {
+    let vaddr = axhal::mem::phys_to_virt(paddr);
+    axalloc::global_allocator().dealloc_pages(vaddr.as_usize(), 1);
+}
+
+/// Drop one reference to a COW frame, freeing it when the count reaches zero.
+fn put_frame(paddr: PhysAddr) {
+    let mut refs = FRAME_REFS.lock();
+    if let Some(cnt) = refs.get_mut(&paddr.as_usize()) {
+        *cnt -= 1;
+        if *cnt == 0 {
+            refs.remove(&paddr.as_usize());
+            drop(refs);
+            free_frame(paddr);
+        }
+    }
+}
+
 #[register_trap_handler(SYSCALL)]
 fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
     ax_println!("handle_syscall [{}] ...", syscall_num);
@@ -125,6 +189,8 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
             tf.arg4() as _,
             tf.arg5() as _,
         ),
+        SYS_MUNMAP => sys_munmap(tf.arg0() as _, tf.arg1() as _),
+        SYS_MPROTECT => sys_mprotect(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
         _ => {
             ax_println!("Unimplemented syscall: {}", syscall_num);
             -LinuxError::ENOSYS.code() as _
@@ -152,7 +218,7 @@ fn sys_mmap(
 ) -> isize {
     let curr = current();
     let mut uspace = curr.task_ext().aspace.lock();
-    
+
     // 在当前进程的虚拟地址空间中，寻找一段空闲的满足要求的连续的虚拟地址
     // 如果 addr 为 NULL，则内核选择（页面对齐）创建映射的地址;这是最便携的创建新映射的方法。
     // 如果 addr 不是 NULL，则 kernel 将其视为放置 Map 位置的提示;
@@ -160,7 +226,7 @@ fn sys_mmap(
     // 如果那里已经存在另一个映射，则内核会选择 一个可能取决于也可能不取决于 hint 的新地址。
     let start = VirtAddr::from_usize(addr as usize + 0x10_0000).align_down_4k();
     let size = length.align_up_4k();
-    let Some(vaddr) = uspace.find_free_area(start, size, 
+    let Some(vaddr) = uspace.find_free_area(start, size,
         VirtAddrRange::from_start_size(uspace.base(), uspace.size())
     ) else {
         ax_println!("mmap: no free area");
@@ -170,33 +236,342 @@ fn sys_mmap(
     ax_println!("expected addr: 0x{:x}, size: {}", addr as usize, length);
     ax_println!("got addr: 0x{:x}, size: {}", vaddr.as_usize(), size);
 
-    // 分配内存空间
+    // 不在此处映射任何物理页，只登记一个 VMA 描述符；
+    // 真正的物理页分配与文件读入推迟到缺页异常时按需完成。
     let prot = MmapProt::from_bits_truncate(prot);
-    if let Err(e) = uspace.map_alloc(vaddr, size, prot.into(), true) {
-        ax_println!("mmap: map memory failed: {}", e);
-        return -LinuxError::ENOMEM.code() as _;
+    let flags = MmapFlags::from_bits_truncate(_flags);
+    VMAS.lock().push(Vma {
+        range: VirtAddrRange::from_start_size(vaddr, size),
+        fd,
+        offset: _offset,
+        prot,
+        flags,
+    });
+
+    vaddr.as_usize() as isize
+}
+
+/// Populate a single faulting page of a demand-paged mapping.
+///
+/// On a fault inside a known VMA this allocates one page, reads `PAGE_SIZE`
+/// bytes from the backing file at the matching offset (zero-filling for
+/// anonymous mappings or past EOF), maps it with the VMA's flags (a private
+/// page is first mapped without write access and made writable by copy-on-write
+/// on its first write) and returns `true` so the faulting instruction is retried. A fault outside any VMA or
+/// violating the VMA's protection bits terminates the task (SIGSEGV-like).
+#[register_trap_handler(PAGE_FAULT)]
+fn handle_page_fault(vaddr: VirtAddr, access_flags: MappingFlags, is_user: bool) -> bool {
+    if !is_user {
+        return false;
+    }
+
+    let fault_page = vaddr.align_down_4k();
+    // 在短作用域内复制出所需字段，确保 `VMAS` 锁在可能的 SIGSEGV 退出前释放。
+    let found = {
+        let vmas = VMAS.lock();
+        vmas.iter().find(|vma| vma.range.contains(fault_page)).map(|vma| {
+            let map_flags: MappingFlags = vma.prot.into();
+            (
+                map_flags,
+                vma.fd,
+                vma.flags.contains(MmapFlags::MAP_ANONYMOUS),
+                vma.flags.contains(MmapFlags::MAP_PRIVATE),
+                vma.offset + (fault_page.as_usize() - vma.range.start.as_usize()) as isize,
+            )
+        })
+    };
+    let Some((map_flags, fd, anonymous, private, file_off)) = found else {
+        ax_println!("page fault: 0x{:x} outside any VMA, killing task", vaddr.as_usize());
+        axtask::exit(-1);
     };
 
-    // 读取文件内容到缓冲区
-    let mut buf = vec![0; length];
-    let Ok(file) = get_file_like(fd) else {
-        ax_println!("mmap: invalid file descriptor");
-        return -LinuxError::EBADF.code() as _;
+    // 访问权限必须是 VMA 声明权限的子集，否则视为非法访问。
+    if !map_flags.contains(access_flags) {
+        ax_println!("page fault: 0x{:x} violates protection, killing task", vaddr.as_usize());
+        axtask::exit(-1);
+    }
+
+    let curr = current();
+    let mut uspace = curr.task_ext().aspace.lock();
+
+    // 页面已存在时，本次缺页来自对只读 COW 页的写操作：执行写时复制。
+    if uspace.query(fault_page).is_ok() {
+        return cow_make_writable(&mut uspace, fault_page, map_flags);
+    }
+
+    // 读出要填充的内容（文件尾部之外补零）。
+    let mut buf = vec![0u8; PAGE_SIZE_4K];
+    if !anonymous {
+        if let Ok(file) = get_file_like(fd) {
+            // 定位到正确的文件偏移，文件尾部之外的字节保持为零。
+            api::sys_lseek(fd, file_off as _, 0);
+            let _ = file.read(&mut buf);
+        }
+    }
+
+    if private {
+        // 私有映射用一块显式分配、线性映射的帧，并登记引用计数；线性映射的
+        // `unmap` 不会把帧还给分配器，帧的生命周期完全由 FRAME_REFS 管理。
+        let Some(paddr) = alloc_frame() else {
+            ax_println!("page fault: alloc frame failed");
+            return false;
+        };
+        // 可写私有页先以只读建立，等到写入时再走 COW 复制。
+        let init_flags = map_flags - MappingFlags::WRITE;
+        if let Err(e) = uspace.map_linear(fault_page, paddr, PAGE_SIZE_4K, init_flags) {
+            ax_println!("page fault: map page failed: {}", e);
+            free_frame(paddr);
+            return false;
+        }
+        FRAME_REFS.lock().insert(paddr.as_usize(), 1);
+    } else {
+        // 共享映射沿用分配型后端，其帧归地址空间所有，`unmap` 时自动回收。
+        if let Err(e) = uspace.map_alloc(fault_page, PAGE_SIZE_4K, map_flags, true) {
+            ax_println!("page fault: map page failed: {}", e);
+            return false;
+        }
+    }
+
+    if let Err(e) = uspace.write(fault_page, &buf) {
+        ax_println!("page fault: fill page failed: {}", e);
+        return false;
+    }
+
+    true
+}
+
+/// Make a copy-on-write page writable for the faulting address space.
+///
+/// If the frame is still shared, a fresh frame is allocated, the contents are
+/// copied into it and the faulting page is re-mapped writable at the new frame;
+/// the shared frame is only unmapped from this address space and never freed
+/// here — only its reference count is dropped (via [`put_frame`]), so other
+/// aspaces keep a valid mapping. A
+/// frame with a single reference is simply promoted to writable in place.
+fn cow_make_writable(
+    uspace: &mut axmm::AddrSpace,
+    fault_page: VirtAddr,
+    map_flags: MappingFlags,
+) -> bool {
+    let Ok((paddr, _, _)) = uspace.query(fault_page) else {
+        return false;
     };
-    if let Err(e) = file.read(&mut buf) {
-        ax_println!("mmap: read file failed: {}", e);
-        return -LinuxError::EIO.code() as _;
+    let key = paddr.as_usize();
+    let shared = FRAME_REFS.lock().get(&key).copied().unwrap_or(0) > 1;
+    if !shared {
+        // 独占该帧（引用计数为 1 或未追踪），就地提升为可写；帧仍由 FRAME_REFS
+        // 追踪，留待 munmap 时回收。
+        if let Err(e) = uspace.protect(fault_page, PAGE_SIZE_4K, map_flags) {
+            ax_println!("cow: protect failed: {}", e);
+            return false;
+        }
+        return true;
+    }
+
+    // 复制出一份新帧：先取出旧内容，解除本 aspace 对共享帧的线性映射（不会释放
+    // 该帧），再把显式分配的可写新帧映射进来并写回。
+    let mut buf = vec![0u8; PAGE_SIZE_4K];
+    if uspace.read(fault_page, &mut buf).is_err() {
+        ax_println!("cow: read old frame failed");
+        return false;
+    }
+    let Some(new_paddr) = alloc_frame() else {
+        ax_println!("cow: alloc new frame failed");
+        return false;
     };
+    if uspace.unmap(fault_page, PAGE_SIZE_4K).is_err() {
+        ax_println!("cow: unmap old frame failed");
+        free_frame(new_paddr);
+        return false;
+    }
+    // 旧帧少了本 aspace 这一份引用，计数归零时由 put_frame 回收。
+    put_frame(paddr);
+    if let Err(e) = uspace.map_linear(fault_page, new_paddr, PAGE_SIZE_4K, map_flags) {
+        ax_println!("cow: map new frame failed: {}", e);
+        free_frame(new_paddr);
+        return false;
+    }
+    FRAME_REFS.lock().insert(new_paddr.as_usize(), 1);
+    if uspace.write(fault_page, &buf).is_err() {
+        ax_println!("cow: fill new frame failed");
+        return false;
+    }
+    true
+}
 
-    // 将缓冲区内容写入到内存区域
-    if let Err(e) = uspace.write(vaddr, &buf) {
-        ax_println!("mmap: write memory failed: {}", e);
-        return -LinuxError::EIO.code() as _;
+/// Release a page's COW reference, if any, when its mapping is torn down.
+///
+/// Pages backed by a tracked COW frame are linearly mapped, so `unmap` never
+/// frees them; the frame must be returned here once its last reference is gone.
+/// Returns `true` if the page was a tracked COW frame (already accounted for).
+fn release_cow_page(uspace: &axmm::AddrSpace, page: VirtAddr) -> bool {
+    let Ok((paddr, _, _)) = uspace.query(page) else {
+        return false;
     };
+    if FRAME_REFS.lock().contains_key(&paddr.as_usize()) {
+        put_frame(paddr);
+        true
+    } else {
+        false
+    }
+}
 
-    ax_println!("mmap: write memory success");
+/// Share every populated private page of `parent` into `child` copy-on-write.
+///
+/// Private pages are backed by tracked, linearly-mapped frames, so installing
+/// the *same* physical frame into both address spaces read-only and bumping the
+/// reference count is safe: neither side owns the frame, and a write on either
+/// faults through [`cow_make_writable`], which duplicates it. This is the path
+/// that gives COW its memory savings: both aspaces share one frame until a write.
+pub fn cow_fork(parent: &mut axmm::AddrSpace, child: &mut axmm::AddrSpace) {
+    let ranges: Vec<VirtAddrRange> = VMAS
+        .lock()
+        .iter()
+        .filter(|vma| vma.flags.contains(MmapFlags::MAP_PRIVATE))
+        .map(|vma| vma.range)
+        .collect();
 
-    vaddr.as_usize() as isize
+    for range in ranges {
+        let mut page = range.start;
+        while page < range.end {
+            if let Ok((paddr, flags, _)) = parent.query(page) {
+                // 只共享受 COW 追踪的线性映射帧；其它后端不在此列。
+                if FRAME_REFS.lock().contains_key(&paddr.as_usize()) {
+                    let ro = flags - MappingFlags::WRITE;
+                    // 父进程页降级为只读，并把同一物理帧共享进子进程。
+                    let _ = parent.protect(page, PAGE_SIZE_4K, ro);
+                    if child.map_linear(page, paddr, PAGE_SIZE_4K, ro).is_ok() {
+                        *FRAME_REFS.lock().entry(paddr.as_usize()).or_insert(1) += 1;
+                    }
+                }
+            }
+            page += PAGE_SIZE_4K;
+        }
+    }
+}
+
+impl Vma {
+    /// Carve `[start, end)` out of this VMA, returning the pieces that survive.
+    ///
+    /// The left piece keeps the original backing offset; the right piece has its
+    /// offset advanced so it still points at the correct file bytes.
+    fn carve_out(&self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        let mut pieces = Vec::new();
+        if self.range.start < start {
+            pieces.push(Vma {
+                range: VirtAddrRange::new(self.range.start, start),
+                fd: self.fd,
+                offset: self.offset,
+                prot: self.prot,
+                flags: self.flags,
+            });
+        }
+        if end < self.range.end {
+            pieces.push(Vma {
+                range: VirtAddrRange::new(end, self.range.end),
+                fd: self.fd,
+                offset: self.offset + (end.as_usize() - self.range.start.as_usize()) as isize,
+                prot: self.prot,
+                flags: self.flags,
+            });
+        }
+        pieces
+    }
+}
+
+/// munmap: 解除一段虚拟地址区间的映射
+///
+/// 拆分或移除与请求区间相交的 VMA，并将底层物理页从地址空间中解除映射，
+/// 使得反复 mmap/munmap 能够正确回收地址空间。
+fn sys_munmap(addr: *mut usize, length: usize) -> isize {
+    let start = VirtAddr::from_usize(addr as usize).align_down_4k();
+    let size = length.align_up_4k();
+    let end = start + size;
+
+    let mut vmas = VMAS.lock();
+    let mut remaining = Vec::new();
+    for vma in vmas.drain(..) {
+        if vma.range.start < end && start < vma.range.end {
+            remaining.extend(vma.carve_out(start, end));
+        } else {
+            remaining.push(vma);
+        }
+    }
+    *vmas = remaining;
+    drop(vmas);
+
+    // 映射是按需分页的，区间内大多数页可能从未缺页，因此只解除真正存在的页。
+    let curr = current();
+    let mut uspace = curr.task_ext().aspace.lock();
+    let mut page = start;
+    while page < end {
+        if uspace.query(page).is_ok() {
+            // 先调和 COW 引用计数（末次引用会回收线性帧），再清除页表项。
+            release_cow_page(&uspace, page);
+            if let Err(e) = uspace.unmap(page, PAGE_SIZE_4K) {
+                ax_println!("munmap: unmap failed: {}", e);
+                return -LinuxError::EINVAL.code() as _;
+            }
+        }
+        page += PAGE_SIZE_4K;
+    }
+    0
+}
+
+/// mprotect: 修改一段虚拟地址区间的保护属性
+///
+/// 对与请求区间重叠的 VMA 修改其 `MmapProt`，当请求只覆盖 VMA 的一部分时，
+/// 在边界处将 VMA 拆开，只改动中间那一段的权限。
+fn sys_mprotect(addr: *mut usize, length: usize, prot: i32) -> isize {
+    let start = VirtAddr::from_usize(addr as usize).align_down_4k();
+    let size = length.align_up_4k();
+    let end = start + size;
+    let new_prot = MmapProt::from_bits_truncate(prot);
+
+    let mut vmas = VMAS.lock();
+    let mut rebuilt = Vec::new();
+    for vma in vmas.drain(..) {
+        if vma.range.start < end && start < vma.range.end {
+            // 保留区间之外的左右两段，中间重叠段换用新权限。
+            rebuilt.extend(vma.carve_out(start, end));
+            let mid_start = vma.range.start.max(start);
+            let mid_end = vma.range.end.min(end);
+            rebuilt.push(Vma {
+                range: VirtAddrRange::new(mid_start, mid_end),
+                fd: vma.fd,
+                offset: vma.offset + (mid_start.as_usize() - vma.range.start.as_usize()) as isize,
+                prot: new_prot,
+                flags: vma.flags,
+            });
+        } else {
+            rebuilt.push(vma);
+        }
+    }
+    *vmas = rebuilt;
+    drop(vmas);
+
+    // 同样只对已缺页建立的页改权限，尚未映射的页待缺页时按新 VMA 权限建立。
+    let curr = current();
+    let mut uspace = curr.task_ext().aspace.lock();
+    let new_flags: MappingFlags = new_prot.into();
+    let mut page = start;
+    while page < end {
+        if let Ok((paddr, _, _)) = uspace.query(page) {
+            let tracked = FRAME_REFS.lock().contains_key(&paddr.as_usize());
+            // 给仍被共享的 COW 页授予写权限时，必须走复制而非直接放开，
+            // 否则会绕过 COW 破坏其它引用者。
+            if tracked && new_flags.contains(MappingFlags::WRITE) {
+                if !cow_make_writable(&mut uspace, page, new_flags) {
+                    return -LinuxError::EINVAL.code() as _;
+                }
+            } else if let Err(e) = uspace.protect(page, PAGE_SIZE_4K, new_flags) {
+                ax_println!("mprotect: protect failed: {}", e);
+                return -LinuxError::EINVAL.code() as _;
+            }
+        }
+        page += PAGE_SIZE_4K;
+    }
+    0
 }
 
 fn sys_openat(dfd: c_int, fname: *const c_char, flags: c_int, mode: api::ctypes::mode_t) -> isize {
@@ -1,9 +1,34 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use core::ptr::NonNull;
 
 use allocator::{AllocError, AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
 
+/// Maximum number of usable physical regions the allocator can track.
+const MAX_REGIONS: usize = 8;
+
+/// A single usable physical range with its own double-ended cursors.
+///
+/// [ bytes-used | avail-area | pages-used ]
+/// |            | -->    <-- |            |
+/// start       b_pos        p_pos       end
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    b_pos: usize,
+    p_pos: usize,
+}
+
+impl Region {
+    const EMPTY: Self = Self { start: 0, end: 0, b_pos: 0, p_pos: 0 };
+
+    const fn new(start: usize, size: usize) -> Self {
+        let end = start + size;
+        Self { start, end, b_pos: start, p_pos: end }
+    }
+}
+
 /// Early memory allocator
 /// Use it before formal bytes-allocator and pages-allocator can work!
 /// This is a double-end memory range:
@@ -18,85 +43,102 @@ use allocator::{AllocError, AllocResult, BaseAllocator, ByteAllocator, PageAlloc
 /// When it goes down to ZERO, free bytes-used area.
 /// For pages area, it will never be freed!
 ///
+/// Several such regions may be tracked at once, so the allocator can serve
+/// bytes and pages across fragmented RAM reported by firmware rather than a
+/// single contiguous block.
 pub struct EarlyAllocator<const PAGE_SIZE: usize> {
-    start: usize,
-    end: usize,
-    b_pos: usize,
-    p_pos: usize,
+    regions: [Region; MAX_REGIONS],
+    nr_regions: usize,
     count: usize,
 }
 
 impl<const PAGE_SIZE: usize> EarlyAllocator<PAGE_SIZE> {
     pub const fn new() -> Self {
-        Self { 
-            start: 0, 
-            end: 0, 
-            b_pos: 0, 
-            p_pos: 0, 
-            count: 0 
+        Self {
+            regions: [Region::EMPTY; MAX_REGIONS],
+            nr_regions: 0,
+            count: 0,
         }
     }
 }
 
 impl<const PAGE_SIZE: usize> BaseAllocator for EarlyAllocator<PAGE_SIZE> {
     fn init(&mut self, start: usize, size: usize) {
-        self.start = start;
-        self.end = start + size;
-        self.b_pos = start;
-        self.p_pos = self.end;
+        self.regions[0] = Region::new(start, size);
+        self.nr_regions = 1;
         self.count = 0;
     }
 
-    fn add_memory(&mut self, _start: usize, _size: usize) -> Result<(), AllocError> {
-        unimplemented!()
+    fn add_memory(&mut self, start: usize, size: usize) -> Result<(), AllocError> {
+        if self.nr_regions >= MAX_REGIONS {
+            return Err(AllocError::NoMemory);
+        }
+        self.regions[self.nr_regions] = Region::new(start, size);
+        self.nr_regions += 1;
+        Ok(())
     }
 }
 
 impl<const PAGE_SIZE: usize> ByteAllocator for EarlyAllocator<PAGE_SIZE> {
     fn alloc(&mut self, layout: core::alloc::Layout) -> allocator::AllocResult<core::ptr::NonNull<u8>> {
         let size = layout.size();
-        if self.b_pos + size > self.p_pos { // 如果分配占用了页区，则没有足够的空间
-            return Err(AllocError::NoMemory);
+        // 依次尝试每个区域的字节游标，选第一个容得下的区域。
+        for region in self.regions[..self.nr_regions].iter_mut() {
+            if region.b_pos + size <= region.p_pos {
+                let ptr = region.b_pos as *mut u8;
+                region.b_pos += size;
+                self.count += 1;
+                return unsafe { Ok(NonNull::new_unchecked(ptr)) };
+            }
         }
-        let ptr = self.b_pos as *mut u8; // 分配的内存地址
-        self.b_pos += size; // 更新字节区位置
-        self.count += 1; // 更新分配次数
-        unsafe { Ok(NonNull::new_unchecked(ptr)) }
+        Err(AllocError::NoMemory)
     }
 
-    fn dealloc(&mut self, _ptr: NonNull<u8>, layout: core::alloc::Layout) {
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: core::alloc::Layout) {
         let size = layout.size();
-        self.b_pos -= size; // 释放内存
+        let top = ptr.as_ptr() as usize + size;
+        // 在 LIFO 释放时，回退拥有该分配的区域的字节游标。
+        if let Some(region) = self.regions[..self.nr_regions]
+            .iter_mut()
+            .find(|region| region.b_pos == top)
+        {
+            region.b_pos -= size;
+        }
         self.count -= 1; // 更新分配次数
         if self.count == 0 {
-            self.b_pos = self.start;
+            // 字节区全部释放，各区域游标回到起点。
+            for region in self.regions[..self.nr_regions].iter_mut() {
+                region.b_pos = region.start;
+            }
         }
     }
 
     fn total_bytes(&self) -> usize {
-        self.end - self.start
+        self.regions[..self.nr_regions].iter().map(|r| r.end - r.start).sum()
     }
 
     fn used_bytes(&self) -> usize {
-        self.b_pos - self.start
+        self.regions[..self.nr_regions].iter().map(|r| r.b_pos - r.start).sum()
     }
 
     fn available_bytes(&self) -> usize {
-        self.p_pos - self.b_pos
+        self.regions[..self.nr_regions].iter().map(|r| r.p_pos - r.b_pos).sum()
     }
 }
 
 impl<const PAGE_SIZE: usize> PageAllocator for EarlyAllocator<PAGE_SIZE> {
     const PAGE_SIZE: usize = PAGE_SIZE;
-    
+
     fn alloc_pages(&mut self, num_pages: usize, _align_pow2: usize) -> AllocResult<usize> {
         let size = num_pages * Self::PAGE_SIZE; // 申请的页数 * 页大小
-        if self.p_pos - size < self.b_pos { // 如果分配占用了字节区，则没有足够的空间
-            return Err(AllocError::NoMemory);
+        // 依次尝试每个区域的页游标，从后往前分配。
+        for region in self.regions[..self.nr_regions].iter_mut() {
+            if region.p_pos >= region.b_pos + size {
+                region.p_pos -= size;
+                return Ok(region.p_pos);
+            }
         }
-        let ptr = self.p_pos - size; // 从后往前分配
-        self.p_pos -= size;
-        Ok(ptr)
+        Err(AllocError::NoMemory)
     }
 
     fn dealloc_pages(&mut self, _pos: usize, _num_pages: usize) {
@@ -104,14 +146,68 @@ impl<const PAGE_SIZE: usize> PageAllocator for EarlyAllocator<PAGE_SIZE> {
     }
 
     fn total_pages(&self) -> usize {
-        (self.end - self.start) / Self::PAGE_SIZE
+        self.regions[..self.nr_regions].iter().map(|r| (r.end - r.start) / Self::PAGE_SIZE).sum()
     }
 
     fn used_pages(&self) -> usize {
-        (self.end - self.p_pos) / Self::PAGE_SIZE
+        self.regions[..self.nr_regions].iter().map(|r| (r.end - r.p_pos) / Self::PAGE_SIZE).sum()
     }
 
     fn available_pages(&self) -> usize {
-        (self.p_pos - self.b_pos) / Self::PAGE_SIZE
+        self.regions[..self.nr_regions].iter().map(|r| (r.p_pos - r.b_pos) / Self::PAGE_SIZE).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::Layout;
+
+    const PAGE: usize = 0x1000;
+
+    #[test]
+    fn add_memory_spans_multiple_regions() {
+        let mut alloc = EarlyAllocator::<PAGE>::new();
+        alloc.init(0x1000_0000, 2 * PAGE);
+        alloc.add_memory(0x2000_0000, 3 * PAGE).unwrap();
+        assert_eq!(alloc.total_bytes(), 5 * PAGE);
+        assert_eq!(alloc.total_pages(), 5);
+        assert_eq!(alloc.available_bytes(), 5 * PAGE);
+    }
+
+    #[test]
+    fn alloc_falls_through_to_later_region() {
+        let mut alloc = EarlyAllocator::<PAGE>::new();
+        alloc.init(0x1000_0000, PAGE);
+        alloc.add_memory(0x2000_0000, 2 * PAGE).unwrap();
+        // 第一块放不下，应从第二块分配。
+        let layout = Layout::from_size_align(PAGE + 1, 1).unwrap();
+        let ptr = alloc.alloc(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize, 0x2000_0000);
+    }
+
+    #[test]
+    fn dealloc_rolls_back_byte_cursor() {
+        let mut alloc = EarlyAllocator::<PAGE>::new();
+        alloc.init(0x1000_0000, PAGE);
+        let layout = Layout::from_size_align(64, 1).unwrap();
+        let p0 = alloc.alloc(layout).unwrap();
+        let _p1 = alloc.alloc(layout).unwrap();
+        assert_eq!(alloc.used_bytes(), 128);
+        // LIFO 释放后字节游标应立即回退，而不是等到计数归零。
+        alloc.dealloc(_p1, layout);
+        assert_eq!(alloc.used_bytes(), 64);
+        alloc.dealloc(p0, layout);
+        assert_eq!(alloc.used_bytes(), 0);
+    }
+
+    #[test]
+    fn add_memory_rejects_overflow() {
+        let mut alloc = EarlyAllocator::<PAGE>::new();
+        alloc.init(0, PAGE);
+        for i in 1..MAX_REGIONS {
+            alloc.add_memory(i * 0x1_0000_0000, PAGE).unwrap();
+        }
+        assert!(alloc.add_memory(0xffff_0000, PAGE).is_err());
     }
 }
@@ -0,0 +1,80 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+/// Address-space identifier.
+///
+/// Tagging mappings with an ASID lets TLB flushes be scoped to a single address
+/// space instead of flushing the whole TLB on every context switch.
+pub type Asid = usize;
+
+/// A recycling allocator for address-space identifiers.
+///
+/// `alloc` first reuses ids returned to the free list by `dealloc`; when the
+/// free list is empty it hands out the next never-used id up to `max_asid`.
+pub struct AsidAllocator {
+    next: Asid,
+    max_asid: Asid,
+    recycled: Vec<Asid>,
+}
+
+impl AsidAllocator {
+    /// Creates an allocator handing out ids in `0..=max_asid`.
+    pub const fn new(max_asid: Asid) -> Self {
+        Self {
+            next: 0,
+            max_asid,
+            recycled: Vec::new(),
+        }
+    }
+
+    /// Allocates an unused ASID, preferring ids popped from the free list.
+    ///
+    /// Returns `None` once every id up to `max_asid` is in use.
+    pub fn alloc(&mut self) -> Option<Asid> {
+        if let Some(id) = self.recycled.pop() {
+            Some(id)
+        } else if self.next <= self.max_asid {
+            let id = self.next;
+            self.next += 1;
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Returns an ASID to the free list so it can be reused.
+    pub fn dealloc(&mut self, id: Asid) {
+        self.recycled.push(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_sequential_ids() {
+        let mut alloc = AsidAllocator::new(2);
+        assert_eq!(alloc.alloc(), Some(0));
+        assert_eq!(alloc.alloc(), Some(1));
+        assert_eq!(alloc.alloc(), Some(2));
+    }
+
+    #[test]
+    fn exhaustion_returns_none() {
+        let mut alloc = AsidAllocator::new(0);
+        assert_eq!(alloc.alloc(), Some(0));
+        assert_eq!(alloc.alloc(), None);
+    }
+
+    #[test]
+    fn recycles_freed_id() {
+        let mut alloc = AsidAllocator::new(0);
+        assert_eq!(alloc.alloc(), Some(0));
+        assert_eq!(alloc.alloc(), None);
+        alloc.dealloc(0);
+        // 回收后应把空闲 id 重新发出，而不是返回 None。
+        assert_eq!(alloc.alloc(), Some(0));
+    }
+}
@@ -30,7 +30,7 @@ where
     entries: Vec<Vec<HashMapEntry<K, V>>>,
     size: usize,
     capacity: usize,
-    capacity_mask: usize,
+    capacity_bits: usize,
     hasher: FibonacciHash,
 }
 
@@ -44,18 +44,19 @@ where
             entries: vec![Vec::new(); 16],
             size: 0,
             capacity: 16,
-            capacity_mask: 15,
+            capacity_bits: 4,
             hasher: FibonacciHash::default(),
         }
     }
 
     pub fn with_capacity(capacity: usize) -> Self {
-        let capacity = capacity.next_power_of_two();
+        // 最小容量为 2，保证 `capacity_bits >= 1`，否则 `hash` 会做 `>> 64` 溢出。
+        let capacity = capacity.next_power_of_two().max(2);
         Self {
             entries: vec![Vec::new(); capacity],
             size: 0,
             capacity,
-            capacity_mask: capacity - 1,
+            capacity_bits: capacity.trailing_zeros() as usize,
             hasher: FibonacciHash::default(),
         }
     }
@@ -63,16 +64,37 @@ where
     pub fn hash(&self, key: &K) -> usize {
         let mut hasher = self.hasher.clone();
         key.hash(&mut hasher);
-        hasher.finish() as usize & self.capacity_mask
+        // 乘法（斐波那契）散列的熵集中在高位，因此取高 `capacity_bits` 位做桶下标。
+        (hasher.finish().wrapping_mul(FIBONACCI_MAGIC) >> (64 - self.capacity_bits)) as usize
     }
 
     pub fn insert(&mut self, key: K, value: V) {
+        if self.size > self.capacity * 3 / 4 {
+            self.rehash();
+        }
         let index = self.hash(&key);
-        let entry = HashMapEntry::new(key, value);
-        self.entries[index].push(entry);
+        // 键已存在则覆盖旧值，与 `std::collections::HashMap` 语义一致。
+        if let Some(entry) = self.entries[index].iter_mut().find(|entry| entry.key == key) {
+            entry.value = value;
+            return;
+        }
+        self.entries[index].push(HashMapEntry::new(key, value));
         self.size += 1;
     }
 
+    /// 负载因子超过 3/4 时，将容量翻倍并把所有条目重新散列到新桶中。
+    fn rehash(&mut self) {
+        self.capacity *= 2;
+        self.capacity_bits += 1;
+        let old = core::mem::replace(&mut self.entries, vec![Vec::new(); self.capacity]);
+        for bucket in old {
+            for entry in bucket {
+                let index = self.hash(&entry.key);
+                self.entries[index].push(entry);
+            }
+        }
+    }
+
     pub fn get(&self, key: &K) -> Option<&V> {
         let index = self.hash(key);
         self.entries[index].iter().find(|entry| entry.key == *key).map(|entry| &entry.value)
@@ -124,3 +146,39 @@ impl<K, V> HashMapEntry<K, V> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrites_duplicate_key() {
+        let mut map = HashMap::new();
+        map.insert(1u32, "a");
+        map.insert(1u32, "b");
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(&1), Some(&"b"));
+    }
+
+    #[test]
+    fn grows_past_load_factor() {
+        let mut map = HashMap::with_capacity(4);
+        for i in 0..100u32 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.len(), 100);
+        // 超过 3/4 负载应自动扩容，所有键仍可检索。
+        assert!(map.capacity() > 4);
+        for i in 0..100u32 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn min_capacity_does_not_overflow_shift() {
+        // capacity 0/1 会被下限抬到 2，避免 `>> 64` 溢出。
+        let mut map = HashMap::with_capacity(1);
+        map.insert(7u32, 7);
+        assert_eq!(map.get(&7), Some(&7));
+    }
+}